@@ -1,18 +1,27 @@
 #![feature(io_error_more)]
 
-use rustls::{OwnedTrustAnchor, ClientConfig, RootCertStore, ClientConnection};
+use rustls::{OwnedTrustAnchor, ClientConfig, RootCertStore, ClientConnection, Certificate, ServerName};
+use rustls::client::{ServerCertVerifier, ServerCertVerified};
 use anyhow::{Result as AResult, bail};
+use base64::Engine;
 use std::net::{TcpStream, ToSocketAddrs, SocketAddr};
 use std::io::{self, ErrorKind, Read, Write, Error as IOError};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::time::{Duration, SystemTime};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::cell::RefCell;
 use std::thread;
 use clap::Parser;
 
-#[derive(Parser, Clone)]
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum AuthMechanism {
+	/// plaintext `LOGIN` with username and password
+	Login,
+	/// SASL XOAUTH2, as required by Gmail and Outlook
+	Xoauth2,
+}
+
+#[derive(Parser)]
 #[command(about = "Uses IMAP IDLE to run a command whenever a new email arrives", long_about = None)]
 pub struct Cli {
 	/// IMAP server domain
@@ -25,15 +34,24 @@ pub struct Cli {
 
 	// the resolved address(es)
 	#[arg(skip)]
-	addrs: RefCell<Vec<SocketAddr>>,
+	addrs: Mutex<Vec<SocketAddr>>,
 
 	/// IMAP user name
 	#[arg(short, long)]
 	username: String,
 
-	/// IMAP password
-	#[arg(short, long)]
-	password: String,
+	/// IMAP password, used for the "login" auth mechanism
+	#[arg(short, long, required_unless_present = "token_command")]
+	password: Option<String>,
+
+	/// authentication mechanism to use when logging in
+	#[arg(long, value_enum, default_value_t = AuthMechanism::Login)]
+	auth_mechanism: AuthMechanism,
+
+	/// command whose stdout is used as the XOAUTH2 access token; required
+	/// when --auth-mechanism is xoauth2
+	#[arg(long)]
+	token_command: Option<PathBuf>,
 
 	/// interval (in seconds) at which to run even if no email arrives
 	#[arg(short, long)]
@@ -43,6 +61,39 @@ pub struct Cli {
 	#[arg(short, long)]
 	command: PathBuf,
 
+	/// mailbox to monitor; repeat to monitor several mailboxes
+	/// concurrently, each over its own connection
+	#[arg(short, long = "mailbox", default_value = "INBOX")]
+	mailboxes: Vec<String>,
+
+	/// seconds an IDLE command is kept open before it is re-armed with a
+	/// fresh DONE/IDLE cycle, to stay under the ~30 minute server timeout
+	/// mandated by RFC 2177
+	#[arg(long, default_value_t = 1740)]
+	idle_refresh: u64,
+
+	/// seconds between NOOP polls when the server doesn't advertise the
+	/// IDLE capability
+	#[arg(long, default_value_t = 60)]
+	poll_interval: u64,
+
+	/// connect in plaintext first and upgrade with STARTTLS, instead of
+	/// the default implicit TLS; implied by --port 143
+	#[arg(long)]
+	starttls: bool,
+
+	/// seconds without any data from the server before treating the
+	/// connection as dropped; 0 disables this and waits forever (IDLE
+	/// re-arming and NOOP polling keep running either way)
+	#[arg(long, default_value_t = 120)]
+	timeout: u64,
+
+	/// skip TLS certificate validation; needed for self-hosted servers
+	/// with an internal CA or a self-signed certificate, at the cost of
+	/// being vulnerable to man-in-the-middle attacks
+	#[arg(long)]
+	danger_accept_invalid_certs: bool,
+
 	/// show all server responses
 	#[arg(short, long, action = clap::ArgAction::Count)]
 	verbose: u8,
@@ -67,9 +118,20 @@ const CANT_CONNECT_ERRORS: &[ErrorKind] = &[
 	ErrorKind::HostUnreachable,
 ];
 
+// how often the main loop wakes up on its own to check whether IDLE needs
+// re-arming or a NOOP poll is due; kept independent of --timeout so that
+// --timeout 0 doesn't also disable those periodic checks
+const READ_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub fn run() -> AResult<()> {
 	let cli = Cli::parse();
 
+	if cli.auth_mechanism == AuthMechanism::Xoauth2 && cli.token_command.is_none() {
+		bail!("--token-command is required when --auth-mechanism is xoauth2");
+	}
+
+	let cli = Arc::new(cli);
+
 	let connection_status: Arc<Mutex<Status>> = Arc::new(Mutex::new(
 		Status { connected: false, last_run: SystemTime::now() }
 	));
@@ -120,6 +182,35 @@ pub fn run() -> AResult<()> {
 		})
 	});
 
+	// we only need the Thread handle (which is Clone) to unpark the timer
+	// from each mailbox's own thread; the JoinHandle itself is never joined
+	let timer_thread = timer_handle.as_ref().map(|h| h.thread().clone());
+
+	// one thread per mailbox, each with its own connection, IDLE session
+	// and reconnect/backoff loop, sharing the connection status and timer
+	let mailbox_threads: Vec<_> = cli.mailboxes.iter().cloned().map(|mailbox| {
+		let cli = Arc::clone(&cli);
+		let connection_status = Arc::clone(&connection_status);
+		let timer_thread = timer_thread.clone();
+
+		thread::spawn(move || run_mailbox(&cli, &mailbox, connection_status, timer_thread))
+	}).collect();
+
+	for handle in mailbox_threads {
+		handle.join().unwrap()?;
+	}
+
+	Ok(())
+}
+
+/// connect to one mailbox, IDLE on it, and reconnect with exponentially
+/// increasing backoff (up to 1/2 hour) whenever the connection drops
+fn run_mailbox(
+	cli: &Cli,
+	mailbox: &str,
+	connection_status: Arc<Mutex<Status>>,
+	timer_thread: Option<thread::Thread>,
+) -> AResult<()> {
 	// what to do as soon as we're connected
 	let connect_callback = || {
 		connection_status.lock().unwrap().connected = true;
@@ -127,21 +218,38 @@ pub fn run() -> AResult<()> {
 		// we unpark the thread after reconnecting since a common cause of
 		// disconnects is suspend, after which the sleep timer might not do what
 		// we want
-		if let Some(th) = &timer_handle {
-			th.thread().unpark();
+		if let Some(th) = &timer_thread {
+			th.unpark();
 		}
 	};
 
 	// what to do when the server tells us we got an email
-	let mail_callback = || {
+	let mail_callback = |mail: &MailInfo| {
 		let mut status = connection_status.lock().unwrap();
 
-		println!("New email, running command ...");
-
-		Command::new(cli.command.as_os_str())
-			.output()
+		println!("New email in {}, running command ...", mail.mailbox);
+
+		let mut child = Command::new(cli.command.as_os_str())
+			.env("IMAPIDLE_MAILBOX", &mail.mailbox)
+			.env("IMAPIDLE_UID", mail.uid.as_deref().unwrap_or_default())
+			.env("IMAPIDLE_FROM", mail.from.as_deref().unwrap_or_default())
+			.env("IMAPIDLE_SUBJECT", mail.subject.as_deref().unwrap_or_default())
+			.env("IMAPIDLE_DATE", mail.date.as_deref().unwrap_or_default())
+			.env("IMAPIDLE_MESSAGE_ID", mail.message_id.as_deref().unwrap_or_default())
+			.stdin(Stdio::piped())
+			.spawn()
 			.expect("command execution failed");
 
+		// always take stdin so it's closed (and the child sees EOF) even
+		// when there are no headers to write, e.g. while polling
+		if let Some(mut stdin) = child.stdin.take() {
+			if let Some(headers) = &mail.headers {
+				let _ = stdin.write_all(headers.as_bytes());
+			}
+		}
+
+		child.wait().expect("command execution failed");
+
 		println!("Command finished.");
 
 		status.last_run = SystemTime::now();
@@ -150,14 +258,14 @@ pub fn run() -> AResult<()> {
 	// reconnect in an infinite loop, with exponentially increasing wait times up to 1/2 hour
 	let mut time_to_reconnect: u64 = 1;
 	loop {
-		return match connect_and_idle(&cli, connect_callback , mail_callback) {
+		return match connect_and_idle(cli, mailbox, connect_callback, mail_callback) {
 			Ok(_) => Ok(()),
 			Err(err) => match err.downcast_ref::<IOError>() {
 				Some(io_err) if CONNECTION_LOST_ERRORS.contains(&io_err.kind()) => {
 					connection_status.lock().unwrap().connected = false;
 
 					time_to_reconnect = 1;
-					println!("Connection lost, reconnecting in {time_to_reconnect} seconds");
+					println!("[{mailbox}] Connection lost, reconnecting in {time_to_reconnect} seconds");
 					thread::sleep(Duration::from_secs(time_to_reconnect));
 
 					continue;
@@ -168,16 +276,16 @@ pub fn run() -> AResult<()> {
 					time_to_reconnect = u64::min(time_to_reconnect*2, 1800);
 
 					if cli.verbose > 0 {
-						println!("Error: {:?}", err);
+						println!("[{mailbox}] Error: {:?}", err);
 					}
-					println!("Cannot connect currently, retrying in {time_to_reconnect} seconds");
+					println!("[{mailbox}] Cannot connect currently, retrying in {time_to_reconnect} seconds");
 
 					thread::sleep(Duration::from_secs(time_to_reconnect));
 
 					continue;
 				},
 				Some(io_err) => {
-					println!("{:?}", io_err.kind());
+					println!("[{mailbox}] {:?}", io_err.kind());
 					Err(err)
 				}
 				_ => Err(err)
@@ -186,46 +294,282 @@ pub fn run() -> AResult<()> {
 	}
 }
 
+/// one parsed IMAP response: the untagged `*` responses the server sends
+/// unprompted, a tagged response that closes out a command, or a `+`
+/// continuation request (e.g. the "+ idling" prompt)
+#[derive(Debug)]
+enum Response {
+	Untagged(String),
+	Tagged { tag: String, text: String },
+	Continuation(String),
+}
+
+/// accumulates bytes read off the wire into complete IMAP responses,
+/// keeping the unconsumed remainder across calls so a response split
+/// across two TLS reads (or one exceeding the read chunk size) is never
+/// corrupted, and so `{n}` literals are read in full before the response
+/// they're embedded in is considered complete
+#[derive(Default)]
+struct ResponseReader {
+	buf: Vec<u8>,
+}
+
+impl ResponseReader {
+	fn feed(&mut self, data: &[u8]) {
+		self.buf.extend_from_slice(data);
+	}
+
+	/// pull the next complete response out of the buffer, if one has
+	/// fully arrived yet
+	fn next_response(&mut self) -> Option<Response> {
+		let mut search_from = 0;
+
+		loop {
+			let newline = self.buf[search_from..].iter().position(|&b|b == b'\n')? + search_from;
+			let line_end = if newline > search_from && self.buf[newline-1] == b'\r' { newline-1 } else { newline };
+
+			if let Some(len) = literal_len(&self.buf[search_from..line_end]) {
+				let literal_start = newline + 1;
+				let needed = literal_start + len;
+
+				if self.buf.len() < needed {
+					// the literal's bytes haven't all arrived yet; wait
+					// for more data instead of treating the response as
+					// complete
+					return None;
+				}
+
+				// the response isn't done at this newline: it continues
+				// past the literal's raw bytes, up to the real
+				// terminating CRLF
+				search_from = needed;
+				continue;
+			}
+
+			let line = self.buf[0..line_end].to_vec();
+			self.buf.drain(0..newline+1);
+			return Some(parse_response(&line));
+		}
+	}
+}
+
+/// if a line ends in an IMAP literal marker like `{45}`, return its byte count
+fn literal_len(line: &[u8]) -> Option<usize> {
+	if line.last() != Some(&b'}') {
+		return None;
+	}
+
+	let open = line.iter().rposition(|&b|b == b'{')?;
+	std::str::from_utf8(&line[open+1..line.len()-1]).ok()?.parse().ok()
+}
+
+fn parse_response(line: &[u8]) -> Response {
+	let text = String::from_utf8_lossy(line).into_owned();
+
+	if let Some(rest) = text.strip_prefix("* ") {
+		Response::Untagged(rest.to_string())
+	} else if let Some(rest) = text.strip_prefix('+') {
+		Response::Continuation(rest.trim_start().to_string())
+	} else if let Some((tag, rest)) = text.split_once(' ') {
+		Response::Tagged { tag: tag.to_string(), text: rest.to_string() }
+	} else {
+		Response::Tagged { tag: text, text: String::new() }
+	}
+}
+
 #[derive(PartialEq, Eq, Debug)]
 enum ImapState {
 	Unauthenticated,
 	Authenticated,
 	Inbox,
-	Idling
+	// waiting for the CAPABILITY response that decides whether we can
+	// IDLE or have to fall back to polling
+	Capability,
+	Idling,
+	// DONE has been sent to re-arm IDLE, waiting for the tagged OK that
+	// closes out the previous idle tag before we send a fresh IDLE
+	Reidling,
+	// IDLE isn't supported; poll with NOOP every `poll_interval` seconds
+	// and watch for the EXISTS count going up
+	Polling,
+	// DONE has been sent so we can FETCH the newly-arrived message(s);
+	// waiting for the tagged OK that closes out the IDLE command
+	PausingForFetch,
+	// FETCH for the newly-arrived message(s) is outstanding
+	Fetching
+}
+
+/// the message count out of an untagged `"n EXISTS"` response
+fn exists_count(text: &str) -> Option<u32> {
+	text.split_whitespace().next()?.parse().ok()
+}
+
+/// metadata about a newly-arrived message, passed to `mail_callback`
+#[derive(Default, Debug)]
+pub struct MailInfo {
+	pub mailbox: String,
+	pub uid: Option<String>,
+	pub from: Option<String>,
+	pub subject: Option<String>,
+	pub date: Option<String>,
+	pub message_id: Option<String>,
+	pub headers: Option<String>,
+}
+
+/// pull out the value following `key` in a FETCH response, e.g. the
+/// number after `"UID"`
+fn field_after<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+	let after = &text[text.find(key)? + key.len()..];
+	after.trim_start().split_whitespace().next()
+}
+
+/// pull the raw bytes of a single IMAP literal (`{n}\r\n<n bytes>`) out of
+/// a FETCH response's text
+fn literal_contents(text: &str) -> Option<&str> {
+	let open = text.rfind('{')?;
+	let close = open + text[open..].find('}')?;
+	let len: usize = text[open+1..close].parse().ok()?;
+	let after = text[close+1..].strip_prefix("\r\n").or_else(||text[close+1..].strip_prefix('\n'))?;
+
+	after.get(0..len)
+}
+
+/// parse the metadata we care about out of one `"n FETCH (...)"` response,
+/// as produced by a `FETCH ... (UID ENVELOPE BODY.PEEK[HEADER.FIELDS (FROM
+/// SUBJECT DATE MESSAGE-ID)])` command
+fn parse_fetch(text: &str) -> MailInfo {
+	let mut info = MailInfo {
+		uid: field_after(text, "UID").map(str::to_string),
+		..Default::default()
+	};
+
+	if let Some(headers) = literal_contents(text) {
+		for line in headers.split("\r\n") {
+			let Some((name, value)) = line.split_once(':') else { continue };
+			let value = value.trim().to_string();
+
+			match name.to_ascii_uppercase().as_str() {
+				"FROM" => info.from = Some(value),
+				"SUBJECT" => info.subject = Some(value),
+				"DATE" => info.date = Some(value),
+				"MESSAGE-ID" => info.message_id = Some(value),
+				_ => {}
+			}
+		}
+
+		info.headers = Some(headers.to_string());
+	}
+
+	info
+}
+
+/// a `ServerCertVerifier` that accepts any certificate, for
+/// --danger-accept-invalid-certs
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &Certificate,
+		_intermediates: &[Certificate],
+		_server_name: &ServerName,
+		_scts: &mut dyn Iterator<Item = &[u8]>,
+		_ocsp_response: &[u8],
+		_now: SystemTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
 }
 
 /// establish a connection to IMAP server, log in, run IDLE command, and wait
 /// for mail to arrive
-pub fn connect_and_idle<F: Fn(), G: Fn()>(cli: &Cli, connected_callback: F, mail_callback: G) -> AResult<()> {
-	let tls_config = ClientConfig::builder()
-		.with_safe_defaults()
-		.with_root_certificates(RootCertStore {
-			roots: webpki_roots::TLS_SERVER_ROOTS.0.iter()
-				.map(|ta| OwnedTrustAnchor::from_subject_spki_name_constraints(
-					ta.subject, ta.spki, ta.name_constraints))
-				.collect()
-		})
-		.with_no_client_auth();
-
+pub fn connect_and_idle<F: Fn(), G: Fn(&MailInfo)>(
+	cli: &Cli, mailbox: &str, connected_callback: F, mail_callback: G
+) -> AResult<()> {
 	let mut buffer = [0u8; 2048];
 
+	let resolved_addrs: Vec<SocketAddr> = {
+		let mut addrs = cli.addrs.lock().unwrap();
+		if addrs.is_empty() {
+			addrs.extend(
+				(cli.server.as_str(), cli.port)
+					.to_socket_addrs()
+					.map_err(|e|io::Error::new(ErrorKind::NotConnected, e.to_string()))?
+			);
+		}
+		addrs.clone()
+	};
+
+	let mut socket = TcpStream::connect(resolved_addrs.as_slice())?;
+	socket.set_read_timeout(
+		if cli.timeout == 0 { None } else { Some(Duration::from_secs(cli.timeout)) }
+	)?;
+
+	// the STARTTLS dance happens in plaintext, before any TLS machinery
+	// touches the socket
+	if cli.starttls || cli.port == 143 {
+		negotiate_starttls(&mut socket, cli.verbose)?;
+	}
+
+	// below, the socket is put on a short, fixed read timeout so the
+	// idle-refresh and NOOP-poll checks keep running on schedule
+	// regardless of --timeout; cli.timeout is instead enforced by hand as
+	// "no data at all for that long", so --timeout 0 can still mean "never
+	// give up on the connection" without starving those periodic checks
+	socket.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+	let mut last_activity = SystemTime::now();
+
+	let tls_config_builder = ClientConfig::builder().with_safe_defaults();
+	let tls_config = if cli.danger_accept_invalid_certs {
+		tls_config_builder
+			.with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+			.with_no_client_auth()
+	} else {
+		tls_config_builder
+			.with_root_certificates(RootCertStore {
+				roots: webpki_roots::TLS_SERVER_ROOTS.0.iter()
+					.map(|ta| OwnedTrustAnchor::from_subject_spki_name_constraints(
+						ta.subject, ta.spki, ta.name_constraints))
+					.collect()
+			})
+			.with_no_client_auth()
+	};
+
 	let mut tls_client = ClientConnection::new(
 		Arc::new(tls_config),
 		cli.server.as_str().try_into().unwrap())?;
 
-	let mut addrs = cli.addrs.borrow_mut();
-	if addrs.is_empty() {
-		addrs.extend(
-			(cli.server.as_str(), cli.port)
-				.to_socket_addrs()
-				.map_err(|e|io::Error::new(ErrorKind::NotConnected, e.to_string()))?
-		);
-	}
+	// STARTTLS upgrades the existing plaintext connection in place, so the
+	// server doesn't send a fresh greeting once the handshake completes;
+	// skip straight to logging in instead of waiting for one
+	let mut state = if cli.starttls || cli.port == 143 {
+		tls_client.writer().write(login_request(cli)?.as_bytes())?;
+		ImapState::Authenticated
+	} else {
+		ImapState::Unauthenticated
+	};
+	let mut responses = ResponseReader::default();
+
+	// counter for the tags used past the hardcoded A001/A002, and the tag
+	// of whatever single command we're currently waiting on
+	let mut tag_counter: u32 = 2;
+	let mut pending_tag = String::new();
+
+	// when the current IDLE command was started, so we know when to
+	// re-arm it
+	let mut idle_since = SystemTime::now();
 
-	let mut socket = TcpStream::connect(addrs.as_slice())?;
-	let mut state = ImapState::Unauthenticated;
+	// whether the server advertised IDLE in its CAPABILITY response, the
+	// last observed message count (from SELECT/NOOP/IDLE), and when we
+	// last polled with NOOP, for the no-IDLE fallback
+	let mut supports_idle = false;
+	let mut message_count: u32 = 0;
+	let mut poll_since = SystemTime::now();
 
-	socket.set_read_timeout(Some(Duration::from_secs(120)))?;
+	// sequence number range of the messages a FETCH is (about to be)
+	// collecting metadata for, and the metadata collected so far
+	let mut fetch_range: Option<(u32, u32)> = None;
+	let mut fetched: Vec<MailInfo> = Vec::new();
 
 	loop {
 		if tls_client.is_handshaking() {
@@ -233,19 +577,61 @@ pub fn connect_and_idle<F: Fn(), G: Fn()>(cli: &Cli, connected_callback: F, mail
 		} else if tls_client.wants_write() {
 			let _o = tls_client.write_tls(&mut socket)?;
 		} else if tls_client.wants_read() {
-			let _i = tls_client.read_tls(&mut socket)?;
+			let _i = match tls_client.read_tls(&mut socket) {
+				Ok(i) => i,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => {
+					// the read_poll_interval tick fired with nothing to read;
+					// give up on the connection if --timeout has been set
+					// and we've gone that long without hearing anything at
+					// all from the server
+					if cli.timeout != 0
+						&& last_activity.elapsed().unwrap_or_default() >= Duration::from_secs(cli.timeout) {
+						return Err(IOError::from(ErrorKind::WouldBlock).into());
+					}
+
+					// that's only a problem once we've been idling long
+					// enough that the server might have dropped the
+					// connection on its own, so just re-arm IDLE in that
+					// case and keep looping otherwise
+					if state == ImapState::Idling
+						&& idle_since.elapsed().unwrap_or_default() >= Duration::from_secs(cli.idle_refresh) {
+						tls_client.writer().write(b"DONE\r\n")?;
+						state = ImapState::Reidling;
+					} else if state == ImapState::Polling
+						&& poll_since.elapsed().unwrap_or_default() >= Duration::from_secs(cli.poll_interval) {
+						tag_counter += 1;
+						pending_tag = format!("A{:03}", tag_counter);
+						tls_client.writer().write(format!("{pending_tag} noop\r\n").as_bytes())?;
+						poll_since = SystemTime::now();
+					}
+
+					continue;
+				},
+				Err(e) => return Err(e.into()),
+			};
+
+			last_activity = SystemTime::now();
 
 			if tls_client.process_new_packets()?.plaintext_bytes_to_read() == 0 {
 				continue;
 			}
 
 			let len = tls_client.reader().read(&mut buffer)?;
+			responses.feed(&buffer[0..len]);
 
-			let responses = buffer[0..len]
-				.split(|&x|x == b'\r' || x == b'\n')
-				.filter(|&x|x.len() != 0);
+			// one read() hands out at most a buffer's worth; drain the rest of
+			// the decrypted plaintext too, since rustls won't ask for more TLS
+			// data (wants_read()) while any of it is left over
+			loop {
+				match tls_client.reader().read(&mut buffer) {
+					Ok(0) => break,
+					Ok(len) => responses.feed(&buffer[0..len]),
+					Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+					Err(e) => return Err(e.into()),
+				}
+			}
 
-			for response in responses {
+			while let Some(response) = responses.next_response() {
 				if cli.verbose > 0 {
 					if state == ImapState::Unauthenticated {
 						if let Some(suite) = tls_client.negotiated_cipher_suite() {
@@ -253,33 +639,164 @@ pub fn connect_and_idle<F: Fn(), G: Fn()>(cli: &Cli, connected_callback: F, mail
 						}
 					}
 
-					println!("{}", String::from_utf8_lossy(response));
+					println!("{:?}", response);
 				}
 
 				match state {
-					ImapState::Unauthenticated => if response.starts_with(b"* OK") {
-						let request = format!("A001 login {} {}\r\n", cli.username, cli.password);
-						tls_client.writer().write(request.as_bytes())?;
-						state = ImapState::Authenticated;
+					ImapState::Unauthenticated => if let Response::Untagged(text) = &response {
+						if text.starts_with("OK") {
+							tls_client.writer().write(login_request(cli)?.as_bytes())?;
+							state = ImapState::Authenticated;
+						}
 					},
-					ImapState::Authenticated => if response.starts_with(b"A001 OK") {
-						tls_client.writer().write(b"A002 select inbox\r\n")?;
-						state = ImapState::Inbox;
-					} else if response.starts_with(b"A001") {
-						bail!("The server rejected authentication");
+					ImapState::Authenticated => if let Response::Continuation(_) = &response {
+						// a rejected/expired XOAUTH2 token gets a SASL error
+						// continuation before the tagged failure; the server
+						// won't send that tagged response until we answer
+						// the continuation with an empty line
+						if cli.auth_mechanism == AuthMechanism::Xoauth2 {
+							tls_client.writer().write(b"\r\n")?;
+						}
+					} else if let Response::Tagged { tag, text } = &response {
+						if tag == "A001" {
+							if text.starts_with("OK") {
+								tls_client.writer().write(format!("A002 select \"{mailbox}\"\r\n").as_bytes())?;
+								state = ImapState::Inbox;
+							} else if cli.auth_mechanism == AuthMechanism::Xoauth2 {
+								// the server commonly rejects a stale access
+								// token; refresh it once and retry before
+								// giving up
+								let token = run_token_command(cli.token_command.as_ref().unwrap())?;
+								let request = xoauth2_request("A001R", &cli.username, &token);
+								tls_client.writer().write(request.as_bytes())?;
+							} else {
+								bail!("The server rejected authentication");
+							}
+						} else if tag == "A001R" {
+							if text.starts_with("OK") {
+								tls_client.writer().write(format!("A002 select \"{mailbox}\"\r\n").as_bytes())?;
+								state = ImapState::Inbox;
+							} else {
+								bail!("The server rejected authentication");
+							}
+						}
+					},
+					ImapState::Inbox => match &response {
+						Response::Untagged(text) if text.ends_with("EXISTS") => {
+							if let Some(n) = exists_count(text) {
+								message_count = n;
+							}
+						},
+						Response::Tagged { tag, text } if tag == "A002" => {
+							if text.starts_with("OK") {
+								tag_counter += 1;
+								pending_tag = format!("A{:03}", tag_counter);
+								tls_client.writer().write(format!("{pending_tag} capability\r\n").as_bytes())?;
+								state = ImapState::Capability;
+							} else {
+								bail!("Selecting inbox failed");
+							}
+						},
+						_ => {}
+					},
+					ImapState::Capability => match &response {
+						Response::Untagged(text) if text.starts_with("CAPABILITY") => {
+							supports_idle = text.split_whitespace().any(|w|w.eq_ignore_ascii_case("IDLE"));
+						},
+						Response::Tagged { tag, text } if *tag == pending_tag => {
+							if !text.starts_with("OK") {
+								bail!("CAPABILITY command failed");
+							}
+
+							if supports_idle {
+								tag_counter += 1;
+								pending_tag = format!("A{:03}", tag_counter);
+								tls_client.writer().write(format!("{pending_tag} idle\r\n").as_bytes())?;
+								state = ImapState::Idling;
+								idle_since = SystemTime::now();
+							} else {
+								println!("Server does not support IDLE, polling every {}s instead", cli.poll_interval);
+								state = ImapState::Polling;
+								poll_since = SystemTime::now();
+							}
+
+							connected_callback();
+							// notify timer thread that we're live
+						},
+						_ => {}
+					},
+					ImapState::Idling => match &response {
+						Response::Continuation(text) if text.starts_with("idling") => {
+							println!("Connected and idling ...");
+						},
+						Response::Untagged(text) if text.ends_with("EXISTS") => {
+							if let Some(n) = exists_count(text) {
+								if n > message_count {
+									fetch_range = Some((message_count + 1, n));
+									tls_client.writer().write(b"DONE\r\n")?;
+									state = ImapState::PausingForFetch;
+								}
+
+								message_count = n;
+							}
+						},
+						_ => {}
 					},
-					ImapState::Inbox => if response.starts_with(b"A002 OK") {
-						tls_client.writer().write(b"A003 idle\r\n")?;
-						state = ImapState::Idling;
-						connected_callback();
-						// notify timer thread that we're live
-					} else if response.starts_with(b"A002") {
-						bail!("Selecting inbox failed");
+					ImapState::Reidling => if let Response::Tagged { tag, text } = &response {
+						if *tag == pending_tag && text.starts_with("OK") {
+							tag_counter += 1;
+							pending_tag = format!("A{:03}", tag_counter);
+
+							tls_client.writer().write(format!("{pending_tag} idle\r\n").as_bytes())?;
+							state = ImapState::Idling;
+							idle_since = SystemTime::now();
+						}
+					},
+					ImapState::Polling => if let Response::Untagged(text) = &response {
+						if text.ends_with("EXISTS") {
+							if let Some(n) = exists_count(text) {
+								if n > message_count {
+									mail_callback(&MailInfo { mailbox: mailbox.to_string(), ..Default::default() });
+								}
+
+								message_count = n;
+							}
+						}
+					},
+					ImapState::PausingForFetch => if let Response::Tagged { tag, text } = &response {
+						if *tag == pending_tag && text.starts_with("OK") {
+							let (lo, hi) = fetch_range.take().unwrap_or((message_count, message_count));
+							let seqs = if lo == hi { lo.to_string() } else { format!("{lo}:{hi}") };
+
+							tag_counter += 1;
+							pending_tag = format!("A{:03}", tag_counter);
+
+							let request = format!(
+								"{pending_tag} fetch {seqs} (UID ENVELOPE BODY.PEEK[HEADER.FIELDS (FROM SUBJECT DATE MESSAGE-ID)])\r\n");
+							tls_client.writer().write(request.as_bytes())?;
+							state = ImapState::Fetching;
+							fetched.clear();
+						}
 					},
-					ImapState::Idling => if response.starts_with(b"+ idling") {
-						println!("Connected and idling ...");
-					} else if response.starts_with(b"*") && response.ends_with(b"EXISTS") {
-						mail_callback();
+					ImapState::Fetching => match &response {
+						Response::Untagged(text) if text.contains("FETCH") => {
+							fetched.push(parse_fetch(text));
+						},
+						Response::Tagged { tag, text } if *tag == pending_tag => {
+							if text.starts_with("OK") {
+								for mut mail in fetched.drain(..) {
+									mail.mailbox = mailbox.to_string();
+									mail_callback(&mail);
+								}
+							}
+
+							tag_counter += 1;
+							pending_tag = format!("A{:03}", tag_counter);
+							tls_client.writer().write(format!("{pending_tag} idle\r\n").as_bytes())?;
+							state = ImapState::Idling;
+							idle_since = SystemTime::now();
+						},
+						_ => {}
 					}
 				}
 			}
@@ -290,3 +807,119 @@ pub fn connect_and_idle<F: Fn(), G: Fn()>(cli: &Cli, connected_callback: F, mail
 		}
 	}
 }
+
+/// run the user-supplied `--token-command` and return its stdout, trimmed,
+/// as the XOAUTH2 access token
+fn run_token_command(token_command: &PathBuf) -> AResult<String> {
+	let output = Command::new(token_command.as_os_str()).output()?;
+
+	if !output.status.success() {
+		bail!("Token command exited with {}", output.status);
+	}
+
+	Ok(String::from_utf8(output.stdout)?.trim_end().to_string())
+}
+
+/// build the tagged `A001` command that logs in, per `cli.auth_mechanism`
+fn login_request(cli: &Cli) -> AResult<String> {
+	Ok(match cli.auth_mechanism {
+		AuthMechanism::Login => format!("A001 login {} {}\r\n",
+			cli.username, cli.password.as_deref().unwrap_or_default()),
+		AuthMechanism::Xoauth2 => {
+			let token = run_token_command(cli.token_command.as_ref().unwrap())?;
+			xoauth2_request("A001", &cli.username, &token)
+		}
+	})
+}
+
+/// build the tagged `AUTHENTICATE XOAUTH2` command with its base64-encoded
+/// SASL initial response
+fn xoauth2_request(tag: &str, username: &str, token: &str) -> String {
+	let initial_response = format!("user={username}\x01auth=Bearer {token}\x01\x01");
+	let encoded = base64::engine::general_purpose::STANDARD.encode(initial_response);
+
+	format!("{tag} AUTHENTICATE XOAUTH2 {encoded}\r\n")
+}
+
+/// speak the plaintext preamble needed to upgrade a connection with
+/// STARTTLS: read the greeting, confirm the server advertises STARTTLS via
+/// CAPABILITY, and request the upgrade. Must complete before any TLS
+/// machinery touches the socket; the actual handshake happens in
+/// `connect_and_idle` right after this returns.
+fn negotiate_starttls(socket: &mut TcpStream, verbose: u8) -> AResult<()> {
+	let mut buffer = [0u8; 2048];
+	let mut responses = ResponseReader::default();
+
+	// pulls the next complete response off the socket, reading and
+	// feeding the incremental parser as many times as it takes; reusing
+	// it here (rather than splitting on CRLF over one read() at a time)
+	// means a greeting or CAPABILITY line split across two TCP segments
+	// is handled the same way it is after the TLS handshake
+	let mut next_response = |socket: &mut TcpStream| -> AResult<Response> {
+		loop {
+			if let Some(response) = responses.next_response() {
+				if verbose > 0 {
+					println!("{:?}", response);
+				}
+
+				return Ok(response);
+			}
+
+			let len = socket.read(&mut buffer)?;
+			if len == 0 {
+				return Err(IOError::from(ErrorKind::ConnectionAborted).into());
+			}
+
+			responses.feed(&buffer[0..len]);
+		}
+	};
+
+	loop {
+		if let Response::Untagged(text) = next_response(socket)? {
+			if text.starts_with("OK") {
+				break;
+			}
+		}
+	}
+
+	socket.write(b"A000 capability\r\n")?;
+
+	let mut has_starttls = false;
+	loop {
+		match next_response(socket)? {
+			Response::Untagged(text) if text.starts_with("CAPABILITY") => {
+				if text.split_whitespace().any(|w| w.eq_ignore_ascii_case("STARTTLS")) {
+					has_starttls = true;
+				}
+			},
+			Response::Tagged { tag, text } if tag == "A000" => {
+				if !text.starts_with("OK") {
+					bail!("CAPABILITY command failed");
+				}
+
+				break;
+			},
+			_ => {},
+		}
+	}
+
+	if !has_starttls {
+		bail!("Server does not advertise STARTTLS support");
+	}
+
+	socket.write(b"A000 starttls\r\n")?;
+
+	loop {
+		if let Response::Tagged { tag, text } = next_response(socket)? {
+			if tag == "A000" {
+				if !text.starts_with("OK") {
+					bail!("STARTTLS command failed");
+				}
+
+				break;
+			}
+		}
+	}
+
+	Ok(())
+}